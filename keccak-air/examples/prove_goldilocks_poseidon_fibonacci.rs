@@ -1,13 +1,19 @@
 use ark_ff::{BigInteger, PrimeField};
 use rand::thread_rng;
+#[cfg(feature = "recursion")]
+use p3_bn254_fr::{Bn254Fr, MdsMatrixBn254};
+use p3_air::{Air, AirBuilder, AirBuilderWithPublicValues, BaseAir};
 use p3_challenger::DuplexChallenger;
+#[cfg(feature = "recursion")]
+use p3_challenger::MultiFieldChallenger;
 use p3_commit::ExtensionMmcs;
 use p3_dft::Radix2DitParallel;
 use p3_field::extension::BinomialExtensionField;
 use p3_field::{AbstractField, Field};
 use p3_fri::{FriConfig, TwoAdicFriPcs};
 use p3_goldilocks::{Goldilocks, MdsMatrixGoldilocks};
-use p3_keccak_air::{FibonacciAir, NUM_FIBONACCI_COLS};
+#[cfg(feature = "poseidon2")]
+use p3_goldilocks::DiffusionMatrixGoldilocks;
 use p3_matrix::dense::RowMajorMatrix;
 use p3_matrix::Matrix;
 use p3_merkle_tree::FieldMerkleTreeMmcs;
@@ -18,12 +24,59 @@ use tracing_forest::util::LevelFilter;
 use tracing_forest::ForestLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing::info;
 use tracing_subscriber::{EnvFilter, Registry};
 use zkhash::fields::goldilocks::FpGoldiLocks;
+#[cfg(any(not(feature = "poseidon2"), feature = "recursion"))]
 use p3_poseidon::Poseidon;
+#[cfg(feature = "poseidon2")]
+use p3_poseidon2::{Poseidon2, Poseidon2ExternalMatrixGeneral};
 
 const WIDTH: usize = 12;
 
+const NUM_FIBONACCI_COLS: usize = 3;
+
+/// An AIR for the row-major `[a, b, a + b]` Fibonacci trace, bound to the claimed seed and
+/// n-th term via public values: `pis = [x0, x1, nth_value]`. The first row's `a`/`b` must
+/// equal the seed, and the last row's running value must equal the claimed n-th term, so
+/// the proof certifies "starting from (x0, x1), the n-th Fibonacci number is nth_value"
+/// rather than an unconstrained trace shape.
+struct FibonacciAir {}
+
+impl<F> BaseAir<F> for FibonacciAir {
+    fn width(&self) -> usize {
+        NUM_FIBONACCI_COLS
+    }
+}
+
+impl<AB: AirBuilderWithPublicValues> Air<AB> for FibonacciAir {
+    fn eval(&self, builder: &mut AB) {
+        let main = builder.main();
+        let pis = builder.public_values();
+
+        let a = pis[0];
+        let b = pis[1];
+        let x = pis[2];
+
+        let local = main.row_slice(0);
+        let next = main.row_slice(1);
+
+        let mut when_first_row = builder.when_first_row();
+        when_first_row.assert_eq(local[0], a);
+        when_first_row.assert_eq(local[1], b);
+
+        // Holds on every row, including the last, so the last row's running value can't be
+        // an unconstrained claim - unlike the `when_transition`-gated constraints below.
+        builder.assert_eq(local[0] + local[1], local[2]);
+
+        let mut when_transition = builder.when_transition();
+        when_transition.assert_eq(local[1], next[0]);
+        when_transition.assert_eq(local[2], next[1]);
+
+        builder.when_last_row().assert_eq(local[2], x);
+    }
+}
+
 fn goldilocks_from_ark_ff(input: FpGoldiLocks) -> Goldilocks {
     let as_bigint = input.into_bigint();
     let mut as_bytes = as_bigint.to_bytes_le();
@@ -32,6 +85,40 @@ fn goldilocks_from_ark_ff(input: FpGoldiLocks) -> Goldilocks {
     Goldilocks::from_wrapped_u64(as_u64)
 }
 
+/// Builds the trace for the Fibonacci AIR starting from the seed pair `(x0, x1)` and
+/// running for `n` rows, so the final row's running value is the term `n + 1` places after
+/// `x0` in the sequence `x0, x1, x0 + x1, ...`. Accumulates in `Val` (reducing mod the field's prime)
+/// rather than `u64`, since the sequence overflows `u64` well before the trace heights
+/// this example sweeps.
+fn generate_fibonacci_trace<Val: AbstractField>(x0: u64, x1: u64, n: usize) -> RowMajorMatrix<Val> {
+    let mut values = Vec::with_capacity(n * NUM_FIBONACCI_COLS);
+    let mut a = Val::from_canonical_u64(x0);
+    let mut b = Val::from_canonical_u64(x1);
+    for _ in 0..n {
+        let c = a.clone() + b.clone();
+        values.push(a);
+        values.push(b.clone());
+        values.push(c.clone());
+        a = b;
+        b = c;
+    }
+    RowMajorMatrix {
+        values,
+        width: NUM_FIBONACCI_COLS,
+    }
+}
+
+/// Reads a benchmark parameter from the `idx`-th positional CLI arg, falling back to the
+/// `env_key` environment variable, then to `default`, so the FRI/trace-height trade-off can
+/// be swept without recompiling: `FIB_LOG_HEIGHT=20 cargo run --release --example ...` or
+/// `cargo run --release --example ... -- 20`.
+fn param<T: std::str::FromStr>(args: &[String], idx: usize, env_key: &str, default: T) -> T {
+    args.get(idx)
+        .and_then(|s| s.parse().ok())
+        .or_else(|| std::env::var(env_key).ok().and_then(|s| s.parse().ok()))
+        .unwrap_or(default)
+}
+
 fn main() -> Result<(), VerificationError> {
     let env_filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
@@ -42,16 +129,44 @@ fn main() -> Result<(), VerificationError> {
         .with(ForestLayer::default())
         .init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let log_height: usize = param(&args, 0, "FIB_LOG_HEIGHT", 6);
+    let log_blowup: usize = param(&args, 1, "FIB_LOG_BLOWUP", 1);
+    let num_queries: usize = param(&args, 2, "FIB_NUM_QUERIES", 100);
+    let proof_of_work_bits: usize = param(&args, 3, "FIB_POW_BITS", 16);
+
     type Val = Goldilocks;
     type Challenge = BinomialExtensionField<Val, 2>;
 
+    #[cfg(not(feature = "poseidon2"))]
     type Perm = Poseidon<Val, MdsMatrixGoldilocks, 8, 7>;
+    #[cfg(not(feature = "poseidon2"))]
     let perm = Perm::new_from_rng(4, 22, MdsMatrixGoldilocks, &mut thread_rng());
 
+    // Poseidon2 splits rounds into full external rounds (round constants + x^7 S-box on
+    // every element, then an external MDS of circulant 4x4 blocks) and partial internal
+    // rounds (a single round constant and S-box on state[0], then M_I = J + diag(d) with
+    // d the MATRIX_DIAG_12_GOLDILOCKS diagonal). This is much cheaper than Poseidon for
+    // the Merkle-heavy hashing FRI commitments do.
+    #[cfg(feature = "poseidon2")]
+    type Perm = Poseidon2<Val, Poseidon2ExternalMatrixGeneral, DiffusionMatrixGoldilocks, WIDTH, 7>;
+    #[cfg(feature = "poseidon2")]
+    let perm = Perm::new_from_rng_128(
+        Poseidon2ExternalMatrixGeneral,
+        DiffusionMatrixGoldilocks,
+        &mut thread_rng(),
+    );
+
+    #[cfg(not(feature = "poseidon2"))]
     type MyHash = PaddingFreeSponge<Perm, 8, 4, 4>;
+    #[cfg(feature = "poseidon2")]
+    type MyHash = PaddingFreeSponge<Perm, WIDTH, 8, 4>;
     let hash = MyHash::new(perm.clone());
 
+    #[cfg(not(feature = "poseidon2"))]
     type MyCompress = TruncatedPermutation<Perm, 2, 4, 8>;
+    #[cfg(feature = "poseidon2")]
+    type MyCompress = TruncatedPermutation<Perm, 2, 4, WIDTH>;
     let compress = MyCompress::new(perm.clone());
 
     type ValMmcs = FieldMerkleTreeMmcs<
@@ -69,30 +184,39 @@ fn main() -> Result<(), VerificationError> {
     type Dft = Radix2DitParallel;
     let dft = Dft {};
 
+    #[cfg(all(not(feature = "poseidon2"), not(feature = "recursion")))]
     type Challenger = DuplexChallenger<Val, Perm, 8>;
+    #[cfg(all(feature = "poseidon2", not(feature = "recursion")))]
+    type Challenger = DuplexChallenger<Val, Perm, WIDTH>;
+
+    // The transcript hasher is swapped for one running over the BN254 scalar field, which
+    // is cheap to re-verify inside an outer Groth16/Plonk circuit, while the trace/Merkle
+    // hashing above stays over Goldilocks.
+    #[cfg(feature = "recursion")]
+    type Perm254 = Poseidon<Bn254Fr, MdsMatrixBn254, 3, 5>;
+    #[cfg(feature = "recursion")]
+    let perm254 = Perm254::new_from_rng(8, 56, MdsMatrixBn254, &mut thread_rng());
+    #[cfg(feature = "recursion")]
+    type Challenger = MultiFieldChallenger<Val, Bn254Fr, Perm254, 3, 2>;
+
+    let num_fibonacci_rows: usize = 1 << log_height;
+    const X0: u64 = 1;
+    const X1: u64 = 1;
+
+    let trace = generate_fibonacci_trace::<Val>(X0, X1, num_fibonacci_rows);
+    // The running value in the last row's final column is the claimed term, the one
+    // `num_fibonacci_rows + 1` places after `X0`.
+    let final_value = trace.values[trace.values.len() - 1];
+    let pis = vec![
+        Val::from_canonical_u64(X0),
+        Val::from_canonical_u64(X1),
+        final_value,
+    ];
 
-    const NUM_FIBONACCI_ROWS: usize = 64;
-    let mut values: Vec<Vec<u64>> = Vec::with_capacity(NUM_FIBONACCI_ROWS);
-    values.push(vec![1, 1, 2]);
-    for i in 1..NUM_FIBONACCI_ROWS {
-        values.push(vec![
-            values[i - 1][1],
-            values[i - 1][2],
-            values[i - 1][1] + values[i - 1][2],
-        ]);
-    }
-    let trace = RowMajorMatrix {
-        values: values
-            .into_iter()
-            .flatten()
-            .map(|x| Val::from_canonical_u64(x))
-            .collect::<Vec<_>>(),
-        width: NUM_FIBONACCI_COLS,
-    };
     let fri_config = FriConfig {
-        log_blowup: 1,
-        num_queries: 100,
-        proof_of_work_bits: 16,
+        log_blowup,
+        num_queries,
+        proof_of_work_bits,
         mmcs: challenge_mmcs,
     };
     type Pcs = TwoAdicFriPcs<Val, Dft, ValMmcs, ChallengeMmcs>;
@@ -109,17 +233,56 @@ fn main() -> Result<(), VerificationError> {
     type MyConfig = StarkConfig<Pcs, Challenge, Challenger>;
     let config = MyConfig::new(pcs);
 
+    let bench_span = tracing::info_span!(
+        "fibonacci_bench",
+        log_height,
+        log_blowup,
+        num_queries,
+        proof_of_work_bits
+    )
+    .entered();
+
+    #[cfg(not(feature = "recursion"))]
     let mut challenger = Challenger::new(perm.clone());
+    #[cfg(feature = "recursion")]
+    let mut challenger = Challenger::new(perm254.clone());
 
-    let proof = prove::<MyConfig, _>(&config, &FibonacciAir {}, &mut challenger, trace, &vec![]);
+    let prove_start = std::time::Instant::now();
+    let proof = prove::<MyConfig, _>(&config, &FibonacciAir {}, &mut challenger, trace, &pis);
+    info!(elapsed = ?prove_start.elapsed(), "prove");
 
+    let encoded_proof = postcard::to_allocvec(&proof).unwrap();
+    info!(bytes = encoded_proof.len(), "proof size");
+    std::fs::write("proof_fibonacci.bin", &encoded_proof).unwrap();
+
+    #[cfg(feature = "json")]
     std::fs::write(
         "proof_fibonacci.json",
         serde_json::to_string(&proof).unwrap(),
     )
         .unwrap();
 
+    // Exercise the full serialize/deserialize/verify cycle rather than verifying the
+    // in-memory proof we just produced.
+    let decoded_proof: p3_uni_stark::Proof<MyConfig> =
+        postcard::from_bytes(&encoded_proof).unwrap();
+
+    #[cfg(not(feature = "recursion"))]
     let mut challenger = Challenger::new(perm);
-    verify(&config, &FibonacciAir {}, &mut challenger, &proof, &vec![]).unwrap();
+    #[cfg(feature = "recursion")]
+    let mut challenger = Challenger::new(perm254);
+
+    let verify_start = std::time::Instant::now();
+    verify(
+        &config,
+        &FibonacciAir {},
+        &mut challenger,
+        &decoded_proof,
+        &pis,
+    )
+    .unwrap();
+    info!(elapsed = ?verify_start.elapsed(), "verify");
+
+    drop(bench_span);
     Ok(())
 }